@@ -1,78 +1,272 @@
+use crate::progress::Progress;
 use crate::Error;
 use bwavfile::{Cue, WaveReader};
 use deluge::{Card, CardFolder, KitBuilder, LocalFileSystem, SamplePath, Sound};
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use walkdir::WalkDir;
+
+/// A WAV input to process together with the root it was discovered under.
+///
+/// The root is the directory the user passed (for recursive directory inputs) or the
+/// file's parent (for individual file arguments); it lets `--preserve-structure`
+/// recreate the source subdirectory tree on the card.
+pub struct SampleInput {
+    pub path: PathBuf,
+    pub root: PathBuf,
+}
+
+/// The outcome of reading and analysing a single source sample, ready to be merged into
+/// a [`KitBuilder`]. Produced by the CPU-bound analysis phase that can run in parallel.
+struct AnalyzedSample {
+    wav_path: PathBuf,
+    destination_file_path: PathBuf,
+    sample_path_in_card: SamplePath,
+    cue_points: Vec<Cue>,
+    /// Content hash of the WAV, present only when `--dedup` is enabled.
+    content_hash: Option<blake3::Hash>,
+}
+
+/// Expand the source arguments into the WAV files to process.
+///
+/// Directory arguments are walked recursively and every `.wav` file found is collected;
+/// individual file arguments are kept as-is. Each input remembers the root it came from
+/// so `--preserve-structure` can compute its path relative to that root.
+pub fn collect_sample_inputs(source_paths: &[PathBuf]) -> Vec<SampleInput> {
+    let mut inputs = Vec::new();
+
+    for source_path in source_paths {
+        if source_path.is_dir() {
+            for entry in WalkDir::new(source_path).into_iter().filter_map(Result::ok) {
+                let path = entry.path();
+                let is_wav = path
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("wav"));
+
+                if path.is_file() && is_wav {
+                    inputs.push(SampleInput {
+                        path: path.to_path_buf(),
+                        root: source_path.clone(),
+                    });
+                }
+            }
+        } else {
+            let root = source_path
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_default();
+
+            inputs.push(SampleInput {
+                path: source_path.clone(),
+                root,
+            });
+        }
+    }
+
+    inputs
+}
 
 pub fn generate_kit_from_regions(
-    source_sample_paths: &[PathBuf],
+    source_samples: &[SampleInput],
     destination_sample_directory: &PathBuf,
     card: &Card<LocalFileSystem>,
     replace_existing_samples: bool,
+    auto_slice: bool,
+    preserve_structure: bool,
+    dedup: bool,
+    progress: &Progress,
+    kit_write_lock: &Mutex<()>,
+    copied_destinations: &Mutex<HashSet<PathBuf>>,
+    sample_path_by_hash: &Mutex<HashMap<blake3::Hash, SamplePath>>,
 ) -> Result<(), Error> {
     // Create the kit patch by building it row by row.
     let mut kit_builder = KitBuilder::default();
     let mut sample_file_path_to_copy = Vec::new();
 
-    for source_sample_path in source_sample_paths {
-        let sample_destination_file_path =
-            get_sample_path(source_sample_path, destination_sample_directory, card)?;
-        let sample_path_in_card = card.sample_path(&sample_destination_file_path)?;
-        let cue_points = read_cue_points(source_sample_path)?;
+    // The analysis phase (WAV decoding, cue parsing and optional onset detection) is
+    // CPU-bound, so run it across all inputs in parallel. The results are collected in
+    // input order so the kit row ordering stays deterministic.
+    let analyzed: Vec<AnalyzedSample> = source_samples
+        .par_iter()
+        .map(|source_sample| {
+            analyze_sample(
+                source_sample,
+                destination_sample_directory,
+                card,
+                auto_slice,
+                preserve_structure,
+                dedup,
+                progress,
+            )
+        })
+        .collect::<Result<Vec<_>, Error>>()?;
 
-        if !cue_points.is_empty() {
-            add_regions_to_kit(&mut kit_builder, &cue_points, sample_path_in_card)?;
-
-            sample_file_path_to_copy.push((source_sample_path, sample_destination_file_path.clone()));
+    // When deduplicating, samples sharing the same content are copied once and every
+    // later duplicate reuses the path of the first copy instead of being copied again.
+    // The map is shared across every input (including the default per-sample kits), so
+    // duplicates are caught regardless of which kit each sample ends up in.
+    for analyzed_sample in analyzed {
+        if analyzed_sample.cue_points.is_empty() {
+            continue;
         }
+
+        let sample_path_in_card = match analyzed_sample.content_hash {
+            Some(hash) => {
+                let mut sample_path_by_hash =
+                    sample_path_by_hash.lock().expect("dedup map poisoned");
+                match sample_path_by_hash.get(&hash) {
+                    Some(existing_sample_path) => {
+                        progress.message(&format!(
+                            "Reusing already copied sample for '{}'",
+                            analyzed_sample.wav_path.to_string_lossy()
+                        ));
+                        existing_sample_path.clone()
+                    }
+                    None => {
+                        sample_path_by_hash
+                            .insert(hash, analyzed_sample.sample_path_in_card.clone());
+                        sample_file_path_to_copy.push((
+                            analyzed_sample.wav_path,
+                            analyzed_sample.destination_file_path,
+                        ));
+                        analyzed_sample.sample_path_in_card
+                    }
+                }
+            }
+            None => {
+                sample_file_path_to_copy.push((
+                    analyzed_sample.wav_path,
+                    analyzed_sample.destination_file_path,
+                ));
+                analyzed_sample.sample_path_in_card
+            }
+        };
+
+        add_regions_to_kit(&mut kit_builder, &analyzed_sample.cue_points, sample_path_in_card)?;
     }
 
     let kit = kit_builder.build().map_err(Error::KitBuilding)?;
-    // Write the kit in the card.
-    let kit_path = card.get_next_standard_patch_path(deluge::PatchType::Kit)?;
-    println!(
-        "Writing kit '{}' with {} row{}",
-        &kit_path.to_string_lossy(),
-        kit.rows.len(),
-        if kit.rows.len() > 1 { "s" } else { "" }
-    );
-    deluge::write_kit_to_file(&kit, &kit_path)?;
+    // Allocating the next patch path and writing to it must be atomic: when several kits
+    // are generated concurrently, two threads would otherwise be handed the same path and
+    // clobber each other's file. Serialize just this allocate→write step.
+    {
+        let _guard = kit_write_lock.lock().expect("kit write lock poisoned");
+        let kit_path = card.get_next_standard_patch_path(deluge::PatchType::Kit)?;
+        progress.message(&format!(
+            "Writing kit '{}' with {} row{}",
+            &kit_path.to_string_lossy(),
+            kit.rows.len(),
+            if kit.rows.len() > 1 { "s" } else { "" }
+        ));
+        deluge::write_kit_to_file(&kit, &kit_path)?;
+    }
 
     // Once the kit has been properly built, copy the samples.
     for (source_sample_path, sample_destination_file_path) in sample_file_path_to_copy {
         copy_sample_if_needed(
-            source_sample_path,
+            &source_sample_path,
             &sample_destination_file_path,
             replace_existing_samples,
+            progress,
+            copied_destinations,
         )?;
     }
 
     Ok(())
 }
 
+/// Read and analyse a single source sample. Apart from advancing the thread-safe progress
+/// counter it mutates no shared state, so it can be called concurrently from the parallel
+/// analysis phase.
+fn analyze_sample(
+    source_sample: &SampleInput,
+    destination_sample_directory: &Path,
+    card: &Card<LocalFileSystem>,
+    auto_slice: bool,
+    preserve_structure: bool,
+    dedup: bool,
+    progress: &Progress,
+) -> Result<AnalyzedSample, Error> {
+    // The regions may be described by an external '.cue' sheet, either passed directly
+    // or sitting next to the sample, in which case the sample actually copied to the
+    // card is the WAV the sheet points at.
+    let (wav_path, cue_sheet_path) = resolve_region_source(&source_sample.path);
+
+    let destination_file_path = get_sample_path(
+        &wav_path,
+        &source_sample.root,
+        destination_sample_directory,
+        card,
+        preserve_structure,
+    )?;
+    let sample_path_in_card = card.sample_path(&destination_file_path)?;
+
+    let mut cue_points = match &cue_sheet_path {
+        Some(cue_sheet_path) => read_cue_sheet(cue_sheet_path, &wav_path)?,
+        None => read_cue_points(&wav_path)?,
+    };
+
+    // When a sample carries no embedded cue points, optionally synthesize regions
+    // by running onset detection so a kit is still generated for it.
+    if cue_points.is_empty() && auto_slice {
+        cue_points = crate::onset::detect_onsets(&wav_path)?;
+    }
+
+    // Only hash samples that will actually contribute rows, and only when deduplicating.
+    let content_hash = if dedup && !cue_points.is_empty() {
+        Some(blake3::hash(&std::fs::read(&wav_path)?))
+    } else {
+        None
+    };
+
+    progress.sample_processed();
+
+    Ok(AnalyzedSample {
+        wav_path,
+        destination_file_path,
+        sample_path_in_card,
+        cue_points,
+        content_hash,
+    })
+}
+
 fn copy_sample_if_needed(
     original_sample_path: &Path,
     destination_sample_path: &Path,
     replace_existing: bool,
+    progress: &Progress,
+    copied_destinations: &Mutex<HashSet<PathBuf>>,
 ) -> Result<(), Error> {
+    // Claim the destination so two concurrent copies that target the same basename do
+    // not write the same file at once; whoever claims it first performs the copy.
+    {
+        let mut destinations = copied_destinations.lock().expect("copy set poisoned");
+        if !destinations.insert(destination_sample_path.to_path_buf()) {
+            return Ok(());
+        }
+    }
+
     if destination_sample_path.exists() && !replace_existing {
-        println!(
+        progress.message(&format!(
             "Sample '{}' already exists.",
             destination_sample_path.display()
-        );
+        ));
         return Ok(());
     }
 
     if destination_sample_path.exists() {
-        println!(
+        progress.message(&format!(
             "Replacing existing sample '{}'",
             destination_sample_path.to_string_lossy()
-        );
+        ));
     } else {
-        println!(
+        progress.message(&format!(
             "Copying sample as '{}'",
             destination_sample_path.to_string_lossy()
-        );
+        ));
     }
 
     if let Some(destination_sample_directory) = destination_sample_path.parent() {
@@ -81,11 +275,93 @@ fn copy_sample_if_needed(
         }
     }
 
-    std::fs::copy(original_sample_path, &destination_sample_path)?;
+    let bytes_copied = std::fs::copy(original_sample_path, &destination_sample_path)?;
+    progress.record_bytes_copied(bytes_copied);
 
     Ok(())
 }
 
+/// Decide where the regions of a source argument come from.
+///
+/// Returns the WAV that will be copied to the card together with the `.cue` sheet to
+/// parse, if any. A source argument that is itself a `.cue` file points at the sibling
+/// WAV; otherwise a `<sample>.cue` sidecar next to the WAV is used when it exists.
+fn resolve_region_source(source_sample_path: &Path) -> (PathBuf, Option<PathBuf>) {
+    if source_sample_path.extension().is_some_and(|ext| ext == "cue") {
+        return (
+            source_sample_path.with_extension("wav"),
+            Some(source_sample_path.to_path_buf()),
+        );
+    }
+
+    let sidecar = source_sample_path.with_extension("cue");
+    let cue_sheet_path = sidecar.is_file().then_some(sidecar);
+
+    (source_sample_path.to_path_buf(), cue_sheet_path)
+}
+
+/// Parse an external `.cue` sheet into regions aligned to `wav_path`.
+///
+/// Each TRACK becomes a region: its INDEX timestamp (MM:SS:FF at 75 fps) is converted to
+/// a sample-frame position using the WAV sample rate, and the length is derived from the
+/// next track or the end of the file, exactly like the embedded-cue fill-in logic. The
+/// track TITLE, when present, is used as the kit row label.
+fn read_cue_sheet(cue_sheet_path: &Path, wav_path: &Path) -> Result<Vec<Cue>, Error> {
+    let sheet = rcue::parser::parse_from_file(&cue_sheet_path.to_string_lossy(), false)
+        .map_err(|error| Error::CueSheet(error.to_string()))?;
+
+    let mut wav_reader = WaveReader::new(File::open(wav_path)?)?;
+    let sample_rate = wav_reader.format()?.sample_rate as f64;
+    let total_length = wav_reader.frame_length()?;
+
+    let mut cue_points = Vec::new();
+    for file in &sheet.files {
+        for track in &file.tracks {
+            // Prefer INDEX 01 (the track's audible start) and fall back to the first index.
+            let start = track
+                .indices
+                .iter()
+                .find(|(number, _)| number == "01")
+                .or_else(|| track.indices.first());
+
+            if let Some((_, timestamp)) = start {
+                let frame = timestamp_to_frame(timestamp.as_secs_f64(), sample_rate);
+
+                // The sheet's timestamps are authored independently of the WAV, so a
+                // track may start at or past the end of the file; skip those rather than
+                // trusting the external sheet and underflowing the length computation.
+                if (frame as u64) < total_length {
+                    cue_points.push(Cue {
+                        ident: track.no.parse().unwrap_or(0),
+                        frame,
+                        length: None,
+                        label: track.title.clone(),
+                        note: None,
+                    });
+                }
+            }
+        }
+    }
+
+    cue_points.sort_by_key(|cue_point| cue_point.frame);
+
+    for i in 0usize..cue_points.len() {
+        if i + 1 < cue_points.len() {
+            cue_points[i].length =
+                Some(cue_points[i + 1].frame.saturating_sub(cue_points[i].frame));
+        } else {
+            cue_points[i].length = Some((total_length - cue_points[i].frame as u64) as u32);
+        }
+    }
+
+    Ok(cue_points)
+}
+
+/// Convert a CUE timestamp, expressed in seconds, to a position in sample frames.
+fn timestamp_to_frame(position_seconds: f64, sample_rate: f64) -> u32 {
+    (position_seconds * sample_rate) as u32
+}
+
 fn read_cue_points(sample_path: &Path) -> Result<Vec<Cue>, Error> {
     let mut wav_reader = WaveReader::new(File::open(&sample_path)?)?;
     let mut cue_points = wav_reader.cue_points()?;
@@ -131,8 +407,10 @@ fn add_regions_to_kit(
 
 fn get_sample_path(
     original_sample_path: &Path,
+    walk_root: &Path,
     destination_sample_directory: &Path,
     card: &Card<LocalFileSystem>,
+    preserve_structure: bool,
 ) -> Result<PathBuf, Error> {
     if !original_sample_path.is_file() {
         return Err(Error::NotAFile(original_sample_path.to_path_buf()));
@@ -156,7 +434,34 @@ fn get_sample_path(
         path
     };
 
-    path.push(original_sample_path.file_name().expect("file name"));
+    // Preserve the source subdirectory tree relative to the walked root, falling back to
+    // the bare file name when the sample does not actually live under that root.
+    match preserve_structure
+        .then(|| original_sample_path.strip_prefix(walk_root).ok())
+        .flatten()
+    {
+        Some(relative_path) => path.push(relative_path),
+        None => path.push(original_sample_path.file_name().expect("file name")),
+    }
 
     Ok(path)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cue_timestamp_converts_to_sample_frame() {
+        // 01:30:37 at 75 fps is 90 + 37/75 seconds; at 44.1 kHz that maps to frame
+        // 3_990_756. Allow one frame of slack for the float-to-integer truncation.
+        let seconds = 90.0 + 37.0 / 75.0;
+        let frame = timestamp_to_frame(seconds, 44_100.0);
+        assert!((frame as i64 - 3_990_756).abs() <= 1, "unexpected frame {frame}");
+    }
+
+    #[test]
+    fn cue_timestamp_zero_is_first_frame() {
+        assert_eq!(timestamp_to_frame(0.0, 44_100.0), 0);
+    }
+}
@@ -3,11 +3,17 @@
 
 use bwavfile::Error as BWavFileError;
 use clap::{Parser, Subcommand, ValueHint};
-use deluge::{Card, CardError, KitBuilderError, LocalFileSystem, WriteError as DelugeWriteError};
+use deluge::{Card, CardError, KitBuilderError, LocalFileSystem, SamplePath, WriteError as DelugeWriteError};
+use progress::Progress;
+use rayon::prelude::*;
+use std::collections::{HashMap, HashSet};
 use std::io::Error as IoError;
 use std::path::PathBuf;
+use std::sync::Mutex;
 
 mod generate_kit;
+mod onset;
+mod progress;
 
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
@@ -37,6 +43,9 @@ pub enum Error {
 
     #[error("'{0}' is not a file")]
     NotAFile(PathBuf),
+
+    #[error("Cue sheet error: {0}")]
+    CueSheet(String),
 }
 
 #[derive(Parser)]
@@ -55,6 +64,11 @@ struct Cli {
     #[clap(short, long, action)]
     force: bool,
 
+    /// Number of worker threads used to process samples in parallel.
+    /// Defaults to the number of available logical cores.
+    #[clap(long)]
+    cores: Option<usize>,
+
     #[clap(subcommand)]
     command: Commands,
 }
@@ -65,8 +79,9 @@ enum Commands {
     /// sample is copied to the specified card into the directory '<root card>/SAMPLES/KITS'.{n}If a
     /// file with the same name already exists in the SAMPLES directory the sample is not copied again, excepted if the flag --force is specified.
     FromRegions {
-        /// The paths of the source samples files.
-        #[clap(value_hint = ValueHint::FilePath)]
+        /// The paths of the source samples.{n}Each path is either a WAV file or a
+        /// directory, which is walked recursively to collect every '.wav' file it contains.
+        #[clap(value_hint = ValueHint::AnyPath)]
         source_sample_paths: Vec<PathBuf>,
 
         /// Specify the directory where the sample is copied.
@@ -79,6 +94,21 @@ enum Commands {
         /// The samples without any regions are ignored.
         #[clap(long)]
         combine_all: bool,
+
+        /// Synthesize regions for samples that carry no embedded cue points by running
+        /// onset detection on them, instead of skipping such samples.
+        #[clap(long)]
+        auto_slice: bool,
+
+        /// Recreate the source subdirectory tree under the destination directory instead
+        /// of flattening every sample into a single directory.
+        #[clap(long)]
+        preserve_structure: bool,
+
+        /// Skip copying samples whose content is identical to one already placed on the
+        /// card, reusing the existing copy in the kit instead.
+        #[clap(long)]
+        dedup: bool,
     },
 }
 
@@ -86,40 +116,84 @@ fn main() -> Result<(), Error> {
     let cli = Cli::parse();
     let card = &Card::open(LocalFileSystem::default(), &cli.card_path)?;
 
+    // Configure the global rayon pool so every parallel phase honours --cores.
+    if let Some(cores) = cli.cores {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(cores)
+            .build_global()
+            .ok();
+    }
+
     // Generate a kit
     match cli.command {
         Commands::FromRegions {
             source_sample_paths,
             destination_sample_directory,
             combine_all,
-        } => match combine_all {
-            true => {
-                if let Err(error) = generate_kit::generate_kit_from_regions(
-                    &source_sample_paths,
-                    &destination_sample_directory,
-                    card,
-                    cli.force,
-                ) {
-                    println!("Error processing multiple samples: {}", error);
-                }
-            }
-            false => {
-                for source_sample_path in &source_sample_paths {
+            auto_slice,
+            preserve_structure,
+            dedup,
+        } => {
+            // Expand directory arguments into the actual WAV files to process.
+            let source_samples = generate_kit::collect_sample_inputs(&source_sample_paths);
+            let progress = Progress::new(source_samples.len() as u64);
+
+            // Shared across every concurrently generated kit: the first serializes patch-path
+            // allocation and writing, the second tracks destinations already being copied,
+            // and the third maps content hashes to samples already placed on the card.
+            let kit_write_lock = Mutex::new(());
+            let copied_destinations = Mutex::new(HashSet::new());
+            let sample_path_by_hash: Mutex<HashMap<blake3::Hash, SamplePath>> =
+                Mutex::new(HashMap::new());
+
+            match combine_all {
+                true => {
                     if let Err(error) = generate_kit::generate_kit_from_regions(
-                        &[source_sample_path.clone()],
+                        &source_samples,
                         &destination_sample_directory,
                         card,
                         cli.force,
+                        auto_slice,
+                        preserve_structure,
+                        dedup,
+                        &progress,
+                        &kit_write_lock,
+                        &copied_destinations,
+                        &sample_path_by_hash,
                     ) {
-                        println!(
-                            "Error processing '{}': {}",
-                            source_sample_path.to_string_lossy(),
-                            error
-                        );
+                        progress
+                            .record_error(&format!("Error processing multiple samples: {}", error));
                     }
                 }
+                false => {
+                    // Each sample produces its own kit; process them in parallel and report
+                    // errors per sample so one bad file does not abort the whole batch.
+                    source_samples.par_iter().for_each(|source_sample| {
+                        if let Err(error) = generate_kit::generate_kit_from_regions(
+                            std::slice::from_ref(source_sample),
+                            &destination_sample_directory,
+                            card,
+                            cli.force,
+                            auto_slice,
+                            preserve_structure,
+                            dedup,
+                            &progress,
+                            &kit_write_lock,
+                            &copied_destinations,
+                            &sample_path_by_hash,
+                        ) {
+                            progress.record_error(&format!(
+                                "Error processing '{}': {}",
+                                source_sample.path.to_string_lossy(),
+                                error
+                            ));
+                        }
+                    });
+                }
             }
-        },
+
+            progress.finish();
+        }
     };
 
     Ok(())
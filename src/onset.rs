@@ -0,0 +1,248 @@
+use crate::Error;
+use bwavfile::{Cue, WaveReader};
+use std::f32::consts::PI;
+use std::fs::File;
+use std::path::Path;
+
+/// Window size, in samples, of the short-time Fourier transform.
+const WINDOW_SIZE: usize = 1024;
+/// Number of samples between the start of two consecutive analysis windows.
+const HOP_SIZE: usize = 512;
+/// Number of frames used to smooth the spectral flux with a moving average.
+const SMOOTHING_WINDOW: usize = 10;
+/// A flux peak is kept when it exceeds the local moving average times this factor.
+const SENSITIVITY_FACTOR: f32 = 1.5;
+/// Minimum gap between two onsets, in seconds, to avoid double-triggers.
+const MIN_INTER_ONSET_SECONDS: f32 = 0.050;
+
+/// Detect onsets in a sample using spectral flux and synthesize one region per onset.
+///
+/// This is the fallback used by `--auto-slice` when a sample carries no embedded cue
+/// chunk: the signal is decoded to mono `f32`, a Hann-windowed STFT is run over
+/// overlapping frames, the spectral flux is peak-picked, and each peak becomes a
+/// synthetic [`Cue`]. Region lengths are filled in exactly like `read_cue_points`, so
+/// the rest of the pipeline is unaware the regions were generated rather than read.
+pub fn detect_onsets(sample_path: &Path) -> Result<Vec<Cue>, Error> {
+    let (samples, sample_rate) = decode_mono(sample_path)?;
+    let total_length = samples.len() as u64;
+
+    let positions = detect_onset_positions(&samples, sample_rate);
+
+    let mut cue_points = Vec::with_capacity(positions.len());
+    for (index, &position) in positions.iter().enumerate() {
+        // Mirror the fill-in logic of `read_cue_points`: a region runs until the next
+        // onset, or until the end of the sample for the last one.
+        let length = if index + 1 < positions.len() {
+            positions[index + 1] - position
+        } else {
+            (total_length - position as u64) as u32
+        };
+
+        cue_points.push(Cue {
+            ident: index as u32 + 1,
+            frame: position,
+            length: Some(length),
+            label: None,
+            note: None,
+        });
+    }
+
+    Ok(cue_points)
+}
+
+/// Run the spectral-flux onset detector over decoded mono samples and return the onset
+/// positions in sample frames. Split out from [`detect_onsets`] so the pure detection
+/// logic can be exercised without a WAV file.
+fn detect_onset_positions(samples: &[f32], sample_rate: u32) -> Vec<u32> {
+    let flux = spectral_flux(samples);
+    let smoothed = moving_average(&flux, SMOOTHING_WINDOW);
+
+    let min_gap_frames = ((MIN_INTER_ONSET_SECONDS * sample_rate as f32) / HOP_SIZE as f32) as usize;
+
+    pick_peaks(&flux, &smoothed, min_gap_frames)
+        .iter()
+        .map(|frame| (frame * HOP_SIZE) as u32)
+        .collect()
+}
+
+/// Decode a WAV file to a single mono channel of `f32` samples, returning the sample rate.
+fn decode_mono(sample_path: &Path) -> Result<(Vec<f32>, u32), Error> {
+    let mut wav_reader = WaveReader::new(File::open(sample_path)?)?;
+    let format = wav_reader.format()?;
+    let channel_count = format.channel_count as usize;
+    let frame_length = wav_reader.frame_length()? as usize;
+
+    let mut reader = wav_reader.audio_frame_reader()?;
+    let mut frame = vec![0i32; channel_count];
+    let mut samples = Vec::with_capacity(frame_length);
+
+    while reader.read_integer_frame(&mut frame)? != 0 {
+        let sum: f32 = frame.iter().map(|&s| s as f32 / i32::MAX as f32).sum();
+        samples.push(sum / channel_count as f32);
+    }
+
+    Ok((samples, format.sample_rate))
+}
+
+/// Compute the spectral flux of the signal: for each frame, the sum over bins of the
+/// positive change in magnitude relative to the previous frame.
+fn spectral_flux(samples: &[f32]) -> Vec<f32> {
+    let window = hann_window();
+    let mut previous = vec![0f32; WINDOW_SIZE / 2];
+    let mut flux = Vec::new();
+
+    let mut start = 0;
+    while start + WINDOW_SIZE <= samples.len() {
+        let mut re: Vec<f32> = (0..WINDOW_SIZE)
+            .map(|i| samples[start + i] * window[i])
+            .collect();
+        let mut im = vec![0f32; WINDOW_SIZE];
+        fft(&mut re, &mut im);
+
+        let magnitude: Vec<f32> = (0..WINDOW_SIZE / 2)
+            .map(|k| (re[k] * re[k] + im[k] * im[k]).sqrt())
+            .collect();
+
+        let value = magnitude
+            .iter()
+            .zip(previous.iter())
+            .map(|(&current, &prev)| (current - prev).max(0.0))
+            .sum();
+        flux.push(value);
+
+        previous = magnitude;
+        start += HOP_SIZE;
+    }
+
+    flux
+}
+
+/// A centered moving average used to smooth the spectral flux before peak-picking.
+fn moving_average(values: &[f32], window: usize) -> Vec<f32> {
+    let half = window / 2;
+    (0..values.len())
+        .map(|i| {
+            let start = i.saturating_sub(half);
+            let end = (i + half + 1).min(values.len());
+            values[start..end].iter().sum::<f32>() / (end - start) as f32
+        })
+        .collect()
+}
+
+/// Pick the frames whose flux is a local maximum above `smoothed * SENSITIVITY_FACTOR`,
+/// enforcing a minimum gap between consecutive onsets.
+fn pick_peaks(flux: &[f32], smoothed: &[f32], min_gap_frames: usize) -> Vec<usize> {
+    let mut onsets = Vec::new();
+    let mut last_onset: Option<usize> = None;
+
+    for i in 1..flux.len().saturating_sub(1) {
+        let is_local_max = flux[i] > flux[i - 1] && flux[i] >= flux[i + 1];
+        let above_threshold = flux[i] > smoothed[i] * SENSITIVITY_FACTOR;
+
+        if is_local_max && above_threshold {
+            if let Some(previous) = last_onset {
+                if i - previous < min_gap_frames {
+                    continue;
+                }
+            }
+
+            onsets.push(i);
+            last_onset = Some(i);
+        }
+    }
+
+    onsets
+}
+
+/// A Hann window of [`WINDOW_SIZE`] samples.
+fn hann_window() -> Vec<f32> {
+    (0..WINDOW_SIZE)
+        .map(|i| 0.5 * (1.0 - (2.0 * PI * i as f32 / WINDOW_SIZE as f32).cos()))
+        .collect()
+}
+
+/// In-place iterative radix-2 Cooley-Tukey FFT. `re.len()` must be a power of two.
+fn fft(re: &mut [f32], im: &mut [f32]) {
+    let n = re.len();
+
+    // Bit-reversal permutation.
+    let mut j = 0;
+    for i in 1..n {
+        let mut bit = n >> 1;
+        while j & bit != 0 {
+            j ^= bit;
+            bit >>= 1;
+        }
+        j ^= bit;
+        if i < j {
+            re.swap(i, j);
+            im.swap(i, j);
+        }
+    }
+
+    let mut len = 2;
+    while len <= n {
+        let angle = -2.0 * PI / len as f32;
+        let (wre, wim) = (angle.cos(), angle.sin());
+        let mut i = 0;
+        while i < n {
+            let (mut cre, mut cim) = (1.0f32, 0.0f32);
+            for k in 0..len / 2 {
+                let a = i + k;
+                let b = i + k + len / 2;
+                let tre = cre * re[b] - cim * im[b];
+                let tim = cre * im[b] + cim * re[b];
+                re[b] = re[a] - tre;
+                im[b] = im[a] - tim;
+                re[a] += tre;
+                im[a] += tim;
+                let next_cre = cre * wre - cim * wim;
+                cim = cre * wim + cim * wre;
+                cre = next_cre;
+            }
+            i += len;
+        }
+        len <<= 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a click train: short bursts of full-scale energy separated by silence.
+    fn click_train(length: usize, clicks: &[usize]) -> Vec<f32> {
+        let mut samples = vec![0.0f32; length];
+        for &click in clicks {
+            for sample in samples.iter_mut().skip(click).take(64) {
+                *sample = 1.0;
+            }
+        }
+        samples
+    }
+
+    #[test]
+    fn detects_one_onset_per_click() {
+        let sample_rate = 44_100;
+        let clicks = [5_000usize, 20_000, 35_000];
+        let samples = click_train(48_000, &clicks);
+
+        let positions = detect_onset_positions(&samples, sample_rate);
+
+        // Every click should be matched by an onset within one analysis window, and the
+        // detector should not invent extra onsets in the silent stretches.
+        assert_eq!(positions.len(), clicks.len());
+        for (&click, &position) in clicks.iter().zip(positions.iter()) {
+            let distance = (position as i64 - click as i64).unsigned_abs();
+            assert!(
+                distance <= WINDOW_SIZE as u64,
+                "onset {position} too far from click {click}"
+            );
+        }
+    }
+
+    #[test]
+    fn silence_produces_no_onsets() {
+        assert!(detect_onset_positions(&vec![0.0f32; 48_000], 44_100).is_empty());
+    }
+}
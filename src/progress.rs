@@ -0,0 +1,86 @@
+use indicatif::{ProgressBar, ProgressStyle};
+use std::io::IsTerminal;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Progress reporting for batch runs.
+///
+/// Wraps an [`indicatif`] progress bar when stdout is a TTY and falls back to plain line
+/// logging otherwise, so piped output stays readable. Every status message produced
+/// during a run is routed through here to keep the bar from being clobbered, and the
+/// running error and byte counters are surfaced alongside the bar.
+pub struct Progress {
+    bar: Option<ProgressBar>,
+    errors: AtomicU64,
+    bytes_copied: AtomicU64,
+}
+
+impl Progress {
+    /// Create a progress reporter sized to the total number of samples to process.
+    pub fn new(total_samples: u64) -> Self {
+        let bar = std::io::stdout().is_terminal().then(|| {
+            let bar = ProgressBar::new(total_samples);
+            bar.set_style(
+                ProgressStyle::with_template("{bar:40} {pos}/{len} samples · {msg}")
+                    .expect("valid progress template"),
+            );
+            bar
+        });
+
+        Self {
+            bar,
+            errors: AtomicU64::new(0),
+            bytes_copied: AtomicU64::new(0),
+        }
+    }
+
+    /// Log a status message without disturbing the progress bar.
+    pub fn message(&self, message: &str) {
+        match &self.bar {
+            Some(bar) => bar.println(message),
+            None => println!("{}", message),
+        }
+    }
+
+    /// Record that one sample finished being analysed.
+    pub fn sample_processed(&self) {
+        if let Some(bar) = &self.bar {
+            bar.inc(1);
+        }
+    }
+
+    /// Record a per-sample error and log the accompanying message.
+    pub fn record_error(&self, message: &str) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        self.message(message);
+        self.refresh_counters();
+    }
+
+    /// Account for bytes written while copying a sample.
+    pub fn record_bytes_copied(&self, bytes: u64) {
+        self.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+        self.refresh_counters();
+    }
+
+    /// Finish the run, leaving a short summary line behind.
+    pub fn finish(&self) {
+        let summary = self.summary();
+        match &self.bar {
+            Some(bar) => bar.finish_with_message(summary),
+            None => println!("Done: {}", summary),
+        }
+    }
+
+    fn refresh_counters(&self) {
+        if let Some(bar) = &self.bar {
+            bar.set_message(self.summary());
+        }
+    }
+
+    fn summary(&self) -> String {
+        format!(
+            "{} error(s), {} bytes copied",
+            self.errors.load(Ordering::Relaxed),
+            self.bytes_copied.load(Ordering::Relaxed)
+        )
+    }
+}